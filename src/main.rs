@@ -1,117 +1,586 @@
 mod util;
 
 use std::fs;
-use std::io::{BufReader, Error};
+use std::io::{BufReader, Error, Read as IoRead};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
 
+use flate2::read::{GzDecoder, ZlibDecoder};
 use quartz_nbt::io::Flavor;
-use quartz_nbt::NbtTag;
+use quartz_nbt::{NbtCompound, NbtTag};
 
 use clap::Parser;
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
 
 use crate::util::{
     ask_for_duplicate_behaviour, ask_for_integer, chunk_position_from_entry_idx,
-    read_bigendian_u32, set_header_entry, DuplicateBehaviour, RegionEntry, SECTOR_SIZE, SIZE_BITS,
-    SIZE_MASK,
+    read_bigendian_u32, set_header_entry, set_timestamp_entry, DuplicateBehaviour, PreferPolicy,
+    RegionEntry, RegionStats, Timestamps, HEADER_ENTRY_COUNT, SECTOR_SIZE, SIZE_BITS, SIZE_MASK,
 };
 use crate::DuplicateBehaviour::{TakeCurrent, TakeUntracked};
 
+const EXTERNAL_FLAG: u8 = 0x80;
+
+/// A chunk payload that's ready to be handed to `quartz_nbt::io::read_nbt`, either borrowed
+/// straight out of the region file or owned because it had to be decompressed or fetched
+/// from a sibling `.mcc` file first.
+enum ChunkPayload<'a> {
+    Borrowed(&'a [u8]),
+    Owned(Vec<u8>),
+}
+
+impl<'a> ChunkPayload<'a> {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            ChunkPayload::Borrowed(bytes) => bytes,
+            ChunkPayload::Owned(bytes) => bytes,
+        }
+    }
+}
+
+/// Resolve a region compression scheme byte (`1`/`2`/`3`/`4`, flag bit already masked off) to
+/// decompressed payload bytes and the flavor `quartz_nbt` should decode them with.
+fn resolve_nbt_payload(scheme: u8, data: &[u8]) -> Option<(ChunkPayload, Flavor)> {
+    match scheme {
+        1 => {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(data).read_to_end(&mut decompressed).ok()?;
+            Some((ChunkPayload::Owned(decompressed), Flavor::Uncompressed))
+        }
+        2 => {
+            let mut decompressed = Vec::new();
+            ZlibDecoder::new(data).read_to_end(&mut decompressed).ok()?;
+            Some((ChunkPayload::Owned(decompressed), Flavor::Uncompressed))
+        }
+        3 => Some((ChunkPayload::Borrowed(data), Flavor::Uncompressed)),
+        4 => {
+            // the vanilla LZ4 scheme prefixes the raw LZ4 block with a big-endian (not the
+            // little-endian `decompress_size_prepended` container) 4-byte uncompressed length
+            if data.len() < 4 {
+                return None;
+            }
+            let uncompressed_len = u32::from_be_bytes(data[..4].try_into().ok()?) as usize;
+            let decompressed = lz4_flex::block::decompress(&data[4..], uncompressed_len).ok()?;
+            Some((ChunkPayload::Owned(decompressed), Flavor::Uncompressed))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_nbt_payload_decodes_big_endian_prefixed_lz4_block() {
+        let original = b"some chunk nbt bytes, repeated, repeated, repeated".to_vec();
+        let compressed = lz4_flex::block::compress(&original);
+        let mut framed = (original.len() as u32).to_be_bytes().to_vec();
+        framed.extend_from_slice(&compressed);
+
+        let (payload, flavor) = resolve_nbt_payload(4, &framed).unwrap();
+        assert!(matches!(flavor, Flavor::Uncompressed));
+        assert_eq!(payload.as_slice(), original.as_slice());
+    }
+
+    /// Build a region body with one entry per `(header_idx, offset, size, fill_byte)`, writing
+    /// its header slot and filling its sectors with `fill_byte` so moved bytes stay traceable.
+    fn region_with_body(sectors: &[(usize, u32, u8, u8)]) -> Vec<u8> {
+        let sector_count = sectors
+            .iter()
+            .map(|&(_, offset, size, _)| offset + size as u32)
+            .max()
+            .unwrap_or(2);
+        let mut bytes = vec![0u8; sector_count as usize * SECTOR_SIZE];
+        for &(header_idx, offset, size, fill) in sectors {
+            set_header_entry(&mut bytes, header_idx * 4, offset as usize, size);
+            let start = offset as usize * SECTOR_SIZE;
+            let end = start + size as usize * SECTOR_SIZE;
+            bytes[start..end].fill(fill);
+        }
+        bytes
+    }
+
+    #[test]
+    fn defragment_resolves_overlapping_entries() {
+        // header 1 physically overlaps header 0's second sector
+        let mut bytes = region_with_body(&[(0, 2, 2, 0xAA), (1, 3, 1, 0xBB)]);
+        let locations = collect_locations(&bytes);
+        assert!(defragment_region(&mut bytes, &locations));
+
+        assert_eq!(collect_locations(&bytes), vec![(0, 2, 2), (1, 4, 1)]);
+        assert_eq!(bytes[2 * SECTOR_SIZE], 0xAA);
+        assert!(bytes[4 * SECTOR_SIZE..5 * SECTOR_SIZE]
+            .iter()
+            .all(|&b| b == 0xBB));
+    }
+
+    #[test]
+    fn defragment_repacks_out_of_order_layout() {
+        // header 0 physically sits after header 1 despite coming first in header order
+        let mut bytes = region_with_body(&[(0, 4, 1, 0xCC), (1, 2, 2, 0xDD)]);
+        let locations = collect_locations(&bytes);
+        assert!(defragment_region(&mut bytes, &locations));
+
+        assert_eq!(collect_locations(&bytes), vec![(0, 2, 1), (1, 3, 2)]);
+        assert!(bytes[2 * SECTOR_SIZE..3 * SECTOR_SIZE]
+            .iter()
+            .all(|&b| b == 0xCC));
+        assert!(bytes[3 * SECTOR_SIZE..5 * SECTOR_SIZE]
+            .iter()
+            .all(|&b| b == 0xDD));
+    }
+
+    #[test]
+    fn defragment_closes_gap_left_by_a_discarded_duplicate() {
+        // sector 3 is unused, as if a larger untracked duplicate used to occupy it
+        let mut bytes = region_with_body(&[(0, 2, 1, 0xEE), (1, 4, 1, 0xFF)]);
+        let locations = collect_locations(&bytes);
+        assert!(defragment_region(&mut bytes, &locations));
+
+        assert_eq!(collect_locations(&bytes), vec![(0, 2, 1), (1, 3, 1)]);
+        assert_eq!(bytes.len(), 4 * SECTOR_SIZE);
+    }
+
+    #[test]
+    fn defragment_truncates_trailing_padding_without_moving_anything() {
+        let mut bytes = region_with_body(&[(0, 2, 1, 0xAA)]);
+        bytes.resize(6 * SECTOR_SIZE, 0);
+        let locations = collect_locations(&bytes);
+        assert!(defragment_region(&mut bytes, &locations));
+
+        assert_eq!(collect_locations(&bytes), vec![(0, 2, 1)]);
+        assert_eq!(bytes.len(), 3 * SECTOR_SIZE);
+    }
+
+    #[test]
+    fn defragment_is_a_noop_when_already_compact() {
+        let mut bytes = region_with_body(&[(0, 2, 1, 0xAA)]);
+        let locations = collect_locations(&bytes);
+        assert!(!defragment_region(&mut bytes, &locations));
+    }
+}
+
+/// Read the external `.mcc` file for a chunk whose region entry has the external-storage flag
+/// set, i.e. `c.<chunkX>.<chunkZ>.mcc` next to the region file.
+fn read_external_chunk(
+    region_dir: &Path,
+    chunk_x: i32,
+    chunk_z: i32,
+    scheme: u8,
+) -> Option<(ChunkPayload<'static>, Flavor)> {
+    let mcc_path = region_dir.join(format!("c.{}.{}.mcc", chunk_x, chunk_z));
+    let raw = fs::read(mcc_path).ok()?;
+    let (payload, flavor) = resolve_nbt_payload(scheme, &raw)?;
+    // `raw` only lives long enough to be decompressed/copied above, so re-own it as 'static
+    let payload = match payload {
+        ChunkPayload::Borrowed(bytes) => ChunkPayload::Owned(bytes.to_vec()),
+        ChunkPayload::Owned(bytes) => ChunkPayload::Owned(bytes),
+    };
+    Some((payload, flavor))
+}
+
+/// Parse a `c.<chunkX>.<chunkZ>.mcc` filename into its chunk coordinates.
+fn parse_mcc_filename(file_name: &std::ffi::OsStr) -> Option<(i32, i32)> {
+    let name = file_name.to_str()?;
+    let mut parts = name.strip_prefix("c.")?.strip_suffix(".mcc")?.split('.');
+    let chunk_x = parts.next()?.parse().ok()?;
+    let chunk_z = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((chunk_x, chunk_z))
+}
+
 /// Look through this byte array for valid region entries, return them all in a collection where the outer Vec is indexed by header idx
 /// and the inner one contains all entries that match that position
-fn discover_all_entries(bytes: &[u8]) -> Vec<Vec<RegionEntry>> {
+fn discover_all_entries(
+    bytes: &[u8],
+    region_position: (i32, i32),
+    region_dir: &Path,
+) -> (Vec<Vec<RegionEntry>>, u32) {
     let mut discovered_entries: Vec<Vec<RegionEntry>> = vec![Vec::new(); SECTOR_SIZE];
+    let mut invalid_sectors: u32 = 0;
+
+    // external stubs found in the sector loop below whose header slot doesn't currently point
+    // back at them, i.e. whose owning chunk we can't identify from the locations table alone.
+    // Resolved against `.mcc` filenames directly after the main loop, see below.
+    let mut orphaned_external_stubs: Vec<(usize, u8, u8)> = Vec::new();
 
     for sector_idx in 2..bytes.len() / SECTOR_SIZE {
         let byte_offset = sector_idx * SECTOR_SIZE;
         let size_bytes = read_bigendian_u32(bytes, byte_offset) as usize;
 
-        let compression_format = bytes[byte_offset + 4];
-        if size_bytes > bytes.len() - byte_offset
-            || (compression_format != 1 && compression_format != 2)
-        {
-            // size or format are invalid, skip
+        if size_bytes > bytes.len() - byte_offset {
+            // size is invalid, skip
+            invalid_sectors += 1;
             continue;
         }
-        let compression_format = if compression_format == 1 {
-            Flavor::GzCompressed
+
+        let compression_byte = bytes[byte_offset + 4];
+        let is_external = compression_byte & EXTERNAL_FLAG != 0;
+        let scheme = compression_byte & !EXTERNAL_FLAG;
+        let size_sectors = f32::ceil(size_bytes as f32 / SECTOR_SIZE as f32) as u8;
+
+        let parsed = if is_external {
+            // the in-region sector holds almost no data for an external entry, so the only way
+            // to find its `.mcc` file from here is to guess the header slot it belongs to from
+            // whichever (possibly stale) locations entry currently points at this sector. This
+            // guess is also used afterwards to sanity-check the coordinates we actually decode.
+            let expected_header_idx = (0..HEADER_ENTRY_COUNT).find(|&idx| {
+                let packed = read_bigendian_u32(bytes, idx * 4);
+                (packed >> SIZE_BITS) as usize == sector_idx
+            });
+
+            match expected_header_idx {
+                Some(header_idx) => {
+                    let (chunk_x, chunk_z) =
+                        chunk_position_from_entry_idx(region_position, header_idx as u16);
+                    read_external_chunk(region_dir, chunk_x, chunk_z, scheme)
+                        .map(|payload_and_flavor| (Some(header_idx), payload_and_flavor))
+                }
+                None => {
+                    // no header slot points at this stub, so its owning chunk can't be guessed
+                    // this way; defer it to the `.mcc`-filename pass below instead of giving up
+                    orphaned_external_stubs.push((sector_idx, scheme, size_sectors));
+                    continue;
+                }
+            }
         } else {
-            Flavor::ZlibCompressed
+            // we now have a valid size, try to decompress (an unknown scheme is rejected here)
+            let slice_start = byte_offset + 4 + 1; // skip the size and format bytes
+            let slice_end = slice_start + size_bytes;
+            resolve_nbt_payload(scheme, &bytes[slice_start..slice_end])
+                .map(|payload_and_flavor| (None, payload_and_flavor))
         };
-        let size_sectors = f32::ceil(size_bytes as f32 / SECTOR_SIZE as f32) as u8;
 
-        // we now have a valid size and format, try to decompress
-        let slice_start = byte_offset + 4 + 1; // skip the size and format bytes
-        let slice_end = slice_start + size_bytes;
+        let Some((expected_header_idx, (payload, flavor))) = parsed else {
+            invalid_sectors += 1;
+            continue;
+        };
 
         //attempt to parse this possible entry as nbt
         let root = quartz_nbt::io::read_nbt(
-            &mut BufReader::new(&mut std::io::Cursor::new(&bytes[slice_start..slice_end])),
-            compression_format,
+            &mut BufReader::new(&mut std::io::Cursor::new(payload.as_slice())),
+            flavor,
         );
 
-        if let Ok(value) = root {
-            // in some earlier versions the Level tag was used, later versions dropped it
-            let level = if value.0.contains_key("Level") {
-                match value.0.get::<_, &NbtTag>("Level").unwrap() {
-                    NbtTag::Compound(t) => t,
-                    _ => {
-                        println!("Found valid compressed entry, but no level tag was found?!");
-                        continue;
-                    }
-                }
-            } else {
-                &value.0
-            };
+        let payload_len = payload.as_slice().len();
+        let entry = root.ok().and_then(|value| {
+            parse_region_entry(
+                &value.0,
+                sector_idx,
+                size_sectors,
+                payload_len,
+                bytes,
+                expected_header_idx,
+            )
+        });
+
+        match entry {
+            Some((header_offset, entry)) => {
+                discovered_entries[header_offset].push(entry);
+            }
+            None => invalid_sectors += 1,
+        }
+    }
 
-            let chunk_x = if let NbtTag::Int(value) = level.get::<_, &NbtTag>("xPos").unwrap() {
-                Some(*value)
-            } else {
-                None
+    if !orphaned_external_stubs.is_empty() {
+        resolve_orphaned_external_stubs(
+            bytes,
+            region_position,
+            region_dir,
+            &mut orphaned_external_stubs,
+            &mut discovered_entries,
+        );
+    }
+    invalid_sectors += orphaned_external_stubs.len() as u32;
+
+    (discovered_entries, invalid_sectors)
+}
+
+/// Match remaining `orphaned_external_stubs` to chunks by enumerating `c.<chunkX>.<chunkZ>.mcc`
+/// files directly, since the locations header can't name the owner of an untracked external
+/// duplicate. Matched stubs are removed from `orphaned_external_stubs` and their entries pushed
+/// into `discovered_entries`; the caller counts any stub left unmatched as an invalid sector.
+fn resolve_orphaned_external_stubs(
+    bytes: &[u8],
+    region_position: (i32, i32),
+    region_dir: &Path,
+    orphaned_external_stubs: &mut Vec<(usize, u8, u8)>,
+    discovered_entries: &mut [Vec<RegionEntry>],
+) {
+    let Ok(dir_entries) = fs::read_dir(region_dir) else {
+        return;
+    };
+
+    for dir_entry in dir_entries.filter_map(|entry| entry.ok()) {
+        if orphaned_external_stubs.is_empty() {
+            break;
+        }
+
+        let Some((chunk_x, chunk_z)) = parse_mcc_filename(&dir_entry.file_name()) else {
+            continue;
+        };
+        if chunk_x.div_euclid(32) != region_position.0
+            || chunk_z.div_euclid(32) != region_position.1
+        {
+            continue;
+        }
+
+        let Ok(raw) = fs::read(dir_entry.path()) else {
+            continue;
+        };
+
+        let header_offset = ((chunk_x & 0x1f) + ((chunk_z & 0x1f) << 5)) as usize;
+
+        let matched = orphaned_external_stubs.iter().enumerate().find_map(
+            |(pool_idx, &(sector_idx, scheme, size_sectors))| {
+                let (payload, flavor) = resolve_nbt_payload(scheme, &raw)?;
+                let root = quartz_nbt::io::read_nbt(
+                    &mut BufReader::new(&mut std::io::Cursor::new(payload.as_slice())),
+                    flavor,
+                )
+                .ok()?;
+                let payload_len = payload.as_slice().len();
+                let (found_header_offset, entry) = parse_region_entry(
+                    &root.0,
+                    sector_idx,
+                    size_sectors,
+                    payload_len,
+                    bytes,
+                    Some(header_offset),
+                )?;
+                Some((pool_idx, found_header_offset, entry))
+            },
+        );
+
+        if let Some((pool_idx, found_header_offset, entry)) = matched {
+            orphaned_external_stubs.swap_remove(pool_idx);
+            discovered_entries[found_header_offset].push(entry);
+        }
+    }
+}
+
+/// A small set of tags that are only present on an actual chunk compound, used to raise
+/// confidence that a blob which happened to decompress into valid NBT really is a chunk.
+const STRUCTURAL_TAGS: [&str; 3] = ["Sections", "sections", "Status"];
+
+/// Validate a decompressed NBT root as a chunk entry, returning its header slot and the entry
+/// itself. Returns `None` instead of panicking for anything that isn't actually chunk data.
+fn parse_region_entry(
+    root: &NbtCompound,
+    sector_idx: usize,
+    size_sectors: u8,
+    payload_len: usize,
+    bytes: &[u8],
+    expected_header_idx: Option<usize>,
+) -> Option<(usize, RegionEntry)> {
+    // in some earlier versions the Level tag was used, later versions dropped it
+    let level = if root.contains_key("Level") {
+        match root.get::<_, &NbtTag>("Level").ok()? {
+            NbtTag::Compound(t) => t,
+            _ => {
+                println!("Found valid compressed entry, but no level tag was found?!");
+                return None;
             }
-            .unwrap();
-            let chunk_z = if let NbtTag::Int(value) = level.get::<_, &NbtTag>("zPos").unwrap() {
-                Some(*value)
-            } else {
-                None
+        }
+    } else {
+        root
+    };
+
+    let chunk_x = match level.get::<_, &NbtTag>("xPos").ok()? {
+        NbtTag::Int(value) => *value,
+        _ => return None,
+    };
+    let chunk_z = match level.get::<_, &NbtTag>("zPos").ok()? {
+        NbtTag::Int(value) => *value,
+        _ => return None,
+    };
+
+    if !STRUCTURAL_TAGS
+        .iter()
+        .any(|tag| level.contains_key(*tag) || root.contains_key(*tag))
+    {
+        return None;
+    }
+
+    let header_offset = ((chunk_x & 0x1f) + ((chunk_z & 0x1f) << 5)) as usize;
+
+    if let Some(expected) = expected_header_idx {
+        if header_offset != expected {
+            return None;
+        }
+    }
+
+    // read the existing header data
+    let existing_packed = read_bigendian_u32(bytes, header_offset * 4);
+    let existing_offset = existing_packed >> SIZE_BITS;
+    let existing_size = (existing_packed & SIZE_MASK) as u8;
+
+    // this is the current entry if the header points to this entry
+    let is_current_entry = existing_offset == sector_idx as u32 && existing_size == size_sectors;
+
+    let data_version = match root.get::<_, &NbtTag>("DataVersion").ok() {
+        Some(NbtTag::Int(value)) => Some(*value),
+        _ => None,
+    };
+
+    let section_count = match level
+        .get::<_, &NbtTag>("sections")
+        .or_else(|_| level.get::<_, &NbtTag>("Sections"))
+    {
+        Ok(NbtTag::List(list)) => list.len() as u32,
+        _ => 0,
+    };
+
+    let entry = RegionEntry {
+        offset_sectors: sector_idx as u32,
+        size_sectors,
+        is_current: is_current_entry,
+        data_version,
+        section_count,
+        payload_len,
+    };
+
+    Some((header_offset, entry))
+}
+
+/// Categorize every header slot's discovered entries into the buckets reported by `--scan-only`.
+/// Bails out to a default (all-zero) `RegionStats` if `bytes` is too small to even hold a
+/// locations header, mirroring the sector loop in `discover_all_entries`, which naturally
+/// no-ops on a truncated or empty file instead of indexing off the end of it.
+fn compute_entry_stats(bytes: &[u8], entries_by_header_idx: &[Vec<RegionEntry>]) -> RegionStats {
+    let mut stats = RegionStats::default();
+
+    if bytes.len() < SECTOR_SIZE {
+        return stats;
+    }
+
+    for (header_idx, entries) in entries_by_header_idx
+        .iter()
+        .take(HEADER_ENTRY_COUNT)
+        .enumerate()
+    {
+        let existing_packed = read_bigendian_u32(bytes, header_idx * 4);
+        let header_points_somewhere = existing_packed >> SIZE_BITS != 0;
+
+        let current_count = entries.iter().filter(|entry| entry.is_current).count();
+        let untracked_count = entries.len() - current_count;
+
+        if current_count == 1 {
+            match untracked_count {
+                0 => stats.single_valid += 1,
+                1 => stats.single_untracked_duplicate += 1,
+                _ => stats.multiple_untracked_duplicates += 1,
             }
-            .unwrap();
+        } else if header_points_somewhere || !entries.is_empty() {
+            stats.dangling_header += 1;
+        }
+    }
 
-            let header_offset = ((chunk_x & 0x1f) + ((chunk_z & 0x1f) << 5)) as usize;
+    stats
+}
 
-            // read the existing header data
-            let existing_packed = read_bigendian_u32(bytes, header_offset * 4);
-            let existing_offset = existing_packed >> SIZE_BITS;
-            let existing_size = (existing_packed & SIZE_MASK) as u8;
+/// The timestamp to write alongside a recovered locations entry: its existing timestamp slot
+/// if that's nonzero, otherwise the region file's mtime, falling back to the current time.
+fn plausible_timestamp(bytes: &[u8], header_idx: usize, file_path: &Path) -> u32 {
+    let existing = Timestamps::parse(bytes).get(header_idx);
+    if existing != 0 {
+        return existing;
+    }
 
-            // this is the current entry if the header points to this entry
-            let is_current_entry =
-                existing_offset == sector_idx as u32 && existing_size == size_sectors;
+    let unix_seconds = |time: std::time::SystemTime| {
+        time.duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as u32)
+            .unwrap_or(0)
+    };
 
-            let entry = RegionEntry {
-                offset_sectors: sector_idx as u32,
-                size_sectors,
-                is_current: is_current_entry,
-            };
+    fs::metadata(file_path)
+        .and_then(|metadata| metadata.modified())
+        .map(unix_seconds)
+        .unwrap_or_else(|_| unix_seconds(std::time::SystemTime::now()))
+}
 
-            // compute if absent
-            let existing_entries = match discovered_entries.get_mut(header_offset) {
-                None => {
-                    discovered_entries.insert(header_offset, Vec::new());
-                    &mut discovered_entries[header_offset]
-                }
-                Some(existing) => existing,
-            };
-            existing_entries.push(entry);
+/// Collect the `(header_idx, offset_sectors, size_sectors)` of every occupied locations entry,
+/// as currently written in `bytes`, in ascending header order.
+fn collect_locations(bytes: &[u8]) -> Vec<(usize, u32, u8)> {
+    let mut locations = Vec::new();
+    for header_idx in 0..HEADER_ENTRY_COUNT {
+        let packed = read_bigendian_u32(bytes, header_idx * 4);
+        let offset = packed >> SIZE_BITS;
+        let size = (packed & SIZE_MASK) as u8;
+        if offset != 0 && size != 0 {
+            locations.push((header_idx, offset, size));
+        }
+    }
+    locations
+}
+
+/// Physically repack the region body from sector 2 onward with no gaps or overlaps, placing each
+/// chunk at the next free 4096-aligned offset. Payloads are read from a pristine snapshot taken
+/// up front so an earlier move can't clobber data a later chunk still needs. `bytes` is
+/// truncated to drop the now-unused tail. Returns whether anything changed.
+fn defragment_region(bytes: &mut Vec<u8>, locations: &[(usize, u32, u8)]) -> bool {
+    let source = bytes.clone();
+
+    let mut placements = Vec::with_capacity(locations.len());
+    let mut write_cursor: u32 = 2;
+    for &(header_idx, offset, size) in locations {
+        let size_sectors = size as u32;
+        let new_offset = write_cursor;
+        placements.push((header_idx, offset, new_offset, size));
+        write_cursor = new_offset + size_sectors;
+    }
+
+    let moved = placements
+        .iter()
+        .any(|&(_, offset, new_offset, _)| offset != new_offset);
+    let body_end = write_cursor as usize * SECTOR_SIZE;
+    let needs_truncate = bytes.len() > body_end;
+
+    if !moved && !needs_truncate {
+        return false;
+    }
+
+    if moved {
+        // the body is being fully repacked, so clear it first rather than leaving stale copies at
+        // old offsets that a later scan could pick up as spurious duplicate entries
+        let body_start = 2 * SECTOR_SIZE;
+        bytes[body_start..].fill(0);
+
+        for (header_idx, offset, new_offset, size) in placements {
+            let len = size as usize * SECTOR_SIZE;
+            let old_start = offset as usize * SECTOR_SIZE;
+            let new_start = new_offset as usize * SECTOR_SIZE;
+
+            if new_start + len > bytes.len() {
+                bytes.resize(new_start + len, 0);
+            }
+
+            bytes[new_start..new_start + len].copy_from_slice(&source[old_start..old_start + len]);
+
+            if new_offset != offset {
+                set_header_entry(bytes, header_idx * 4, new_offset as usize, size);
+            }
         }
     }
 
-    discovered_entries
+    // `write_cursor` can never end up below 2 (the header+timestamp sectors), so this never
+    // truncates into the part of the file defragmentation doesn't own
+    bytes.truncate(body_end);
+
+    true
 }
 
 fn recover_entries(
     file_path: &Path,
     duplicate_behaviour: Option<DuplicateBehaviour>,
-) -> Result<(), Error> {
+    defragment: bool,
+    scan_only: bool,
+    prefer: Option<PreferPolicy>,
+) -> Result<RegionStats, Error> {
     let mut bytes = fs::read(file_path)?;
 
     let file_name = file_path.file_name().unwrap().to_str().unwrap().to_owned();
@@ -121,7 +590,16 @@ fn recover_entries(
         file_name_split[2].parse().unwrap(),
     );
 
-    let entries_by_header_idx = discover_all_entries(&bytes);
+    let region_dir = file_path.parent().unwrap_or_else(|| Path::new("."));
+    let (entries_by_header_idx, invalid_sectors) =
+        discover_all_entries(&bytes, region_position, region_dir);
+
+    let mut stats = compute_entry_stats(&bytes, &entries_by_header_idx);
+    stats.invalid_sectors = invalid_sectors;
+
+    if scan_only {
+        return Ok(stats);
+    }
 
     let mut any_recovered = false;
 
@@ -185,7 +663,6 @@ fn recover_entries(
                 entry_to_save = entries.iter().find(|entry| !entry.is_current).unwrap();
                 println!("Chunk ({}, {}) recovered unknown entry!", chunk_x, chunk_z);
             } else if has_multiple_untracked {
-                // there are multiple untracked, so allow user to pick from them
                 let mut untracked_entries = Vec::new();
                 for entry in entries {
                     if !entry.is_current {
@@ -193,25 +670,52 @@ fn recover_entries(
                     }
                 }
 
-                println!(
-                    "Which unknown entry should be chosen (1 to {})?",
-                    untracked_entries.len()
-                );
-                let entry_idx = ask_for_integer(|value| value > 0) - 1;
-                entry_to_save = untracked_entries[entry_idx as usize];
-                println!("Chunk ({}, {}) recovered unknown entry!", chunk_x, chunk_z);
+                if let Some(policy) = prefer {
+                    // auto-select the best untracked candidate instead of prompting
+                    let chosen = *untracked_entries
+                        .iter()
+                        .max_by_key(|entry| policy.score(entry))
+                        .unwrap();
+                    println!(
+                        "Chunk ({}, {}) auto-selected untracked entry via {:?} (score {})",
+                        chunk_x,
+                        chunk_z,
+                        policy,
+                        policy.score(chosen)
+                    );
+                    entry_to_save = chosen;
+                } else {
+                    // there are multiple untracked, so allow user to pick from them
+                    println!(
+                        "Which unknown entry should be chosen (1 to {})?",
+                        untracked_entries.len()
+                    );
+                    let entry_idx = ask_for_integer(|value| value > 0) - 1;
+                    entry_to_save = untracked_entries[entry_idx as usize];
+                    println!("Chunk ({}, {}) recovered unknown entry!", chunk_x, chunk_z);
+                }
             } else {
                 panic!("Should never be reached");
             }
         }
 
         any_recovered = true;
+        stats.recovered += 1;
+        let timestamp = plausible_timestamp(&bytes, header_idx, file_path);
         set_header_entry(
             &mut bytes,
             header_idx * 4,
             entry_to_save.offset_sectors as usize,
             entry_to_save.size_sectors as u8,
         );
+        set_timestamp_entry(&mut bytes, header_idx, timestamp);
+    }
+
+    if defragment {
+        let locations = collect_locations(&bytes);
+        if defragment_region(&mut bytes, &locations) {
+            any_recovered = true;
+        }
     }
 
     if any_recovered {
@@ -222,7 +726,7 @@ fn recover_entries(
         );
     }
 
-    Ok(())
+    Ok(stats)
 }
 
 #[derive(Parser, Debug)]
@@ -233,30 +737,127 @@ struct Args {
 
     #[clap(long, short)]
     pub duplicate_behaviour: Option<DuplicateBehaviour>,
+
+    /// After recovery, physically relocate chunks to resolve any sector overlaps and compact
+    /// the region file
+    #[clap(long)]
+    pub defragment: bool,
+
+    /// Scan and report what recovery would do without writing anything or prompting for
+    /// duplicate resolution
+    #[clap(long)]
+    pub scan_only: bool,
+
+    /// When a chunk has multiple untracked duplicates, automatically choose one by this policy
+    /// instead of prompting
+    #[clap(long)]
+    pub prefer: Option<PreferPolicy>,
+}
+
+fn print_stats_summary(total: &RegionStats) {
+    println!("Scan summary across world:");
+    println!(
+        "  chunks with a single valid entry:          {}",
+        total.single_valid
+    );
+    println!(
+        "  chunks with one untracked duplicate:       {}",
+        total.single_untracked_duplicate
+    );
+    println!(
+        "  chunks with multiple untracked duplicates: {}",
+        total.multiple_untracked_duplicates
+    );
+    println!(
+        "  chunks with a dangling header pointer:     {}",
+        total.dangling_header
+    );
+    println!(
+        "  sectors with no valid entry:                {}",
+        total.invalid_sectors
+    );
+}
+
+fn process_region(
+    path: &Path,
+    args: &Args,
+    progress: &ProgressBar,
+    recovered_so_far: &AtomicU32,
+) -> RegionStats {
+    let stats = match recover_entries(
+        path,
+        args.duplicate_behaviour,
+        args.defragment,
+        args.scan_only,
+        args.prefer,
+    ) {
+        Ok(stats) => stats,
+        Err(err) => {
+            println!("Error parsing region file {}", err);
+            RegionStats::default()
+        }
+    };
+
+    let total_recovered =
+        recovered_so_far.fetch_add(stats.recovered, Ordering::Relaxed) + stats.recovered;
+    progress.set_message(format!("{} chunks recovered", total_recovered));
+    progress.inc(1);
+
+    stats
 }
 
 fn main() -> std::io::Result<()> {
     let args = Args::parse();
 
     let world_path = args.world_path.join("region");
-
     let world_path = Path::new(&world_path);
-    for entry in fs::read_dir(world_path)? {
-        let entry = entry?;
-        let entry_path = entry.path();
-        if !entry_path.is_dir() {
-            let extension = entry_path.extension();
-            if let Some(ext) = extension {
-                if ext.to_str().unwrap().ends_with("mca") {
-                    match recover_entries(&entry.path(), args.duplicate_behaviour) {
-                        Ok(_) => {}
-                        Err(err) => {
-                            println!("Error parsing region file {}", err);
-                        }
-                    }
-                }
-            }
-        }
+
+    let region_paths: Vec<PathBuf> = fs::read_dir(world_path)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            !path.is_dir()
+                && path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| ext.ends_with("mca"))
+        })
+        .collect();
+
+    let progress = ProgressBar::new(region_paths.len() as u64);
+    progress.set_style(
+        ProgressStyle::with_template("{bar:40.cyan/blue} {pos}/{len} regions | {msg}").unwrap(),
+    );
+    let recovered_so_far = AtomicU32::new(0);
+
+    // interactive duplicate prompts read from stdin, which isn't safe to do from multiple
+    // threads at once, so we only go parallel once there's no prompting left to do. A chunk
+    // with multiple untracked duplicates still prompts via `ask_for_integer` regardless of
+    // `duplicate_behaviour` unless `--prefer` is also set, so both are required here.
+    let can_run_parallel =
+        (args.duplicate_behaviour.is_some() && args.prefer.is_some()) || args.scan_only;
+
+    let total_stats = if can_run_parallel {
+        region_paths
+            .par_iter()
+            .map(|path| process_region(path, &args, &progress, &recovered_so_far))
+            .reduce(RegionStats::default, |mut acc, stats| {
+                acc.merge(&stats);
+                acc
+            })
+    } else {
+        region_paths
+            .iter()
+            .fold(RegionStats::default(), |mut acc, path| {
+                acc.merge(&process_region(path, &args, &progress, &recovered_so_far));
+                acc
+            })
+    };
+
+    progress.finish();
+
+    if args.scan_only {
+        print_stats_summary(&total_stats);
     }
 
     Ok(())
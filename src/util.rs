@@ -4,6 +4,11 @@ use crate::util::DuplicateBehaviour::{TakeCurrent, TakeUntracked};
 
 pub const SECTOR_SIZE: usize = 4096;
 
+// the locations header occupies the first `SECTOR_SIZE` bytes of the file as 1024 packed
+// 4-byte (offset, size) entries, one per header slot; `SECTOR_SIZE` itself is a byte count; not
+// the entry count, so anything iterating header slots must use this instead.
+pub const HEADER_ENTRY_COUNT: usize = SECTOR_SIZE / 4;
+
 pub const SIZE_BITS: u32 = 8;
 pub const SIZE_MASK: u32 = (1 << SIZE_BITS) - 1;
 
@@ -18,6 +23,56 @@ pub struct RegionEntry {
     pub is_current: bool, // is the current entry referenced in the header
     pub offset_sectors: u32,
     pub size_sectors: u8,
+    pub data_version: Option<i32>, // the chunk's `DataVersion`, if present
+    pub section_count: u32,        // number of entries in `sections`/`Sections`
+    pub payload_len: usize,        // size in bytes of the entry's decompressed payload
+}
+
+/// Policy used to automatically choose between several untracked duplicates of the same chunk,
+/// instead of prompting the user to pick one by index.
+///
+/// There is no `NewestTimestamp` option: the region's timestamp table has one entry per header
+/// slot, not per physical duplicate, so every untracked candidate for a chunk would read back the
+/// same value and the policy couldn't actually differentiate between them.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, clap::ValueEnum)]
+pub enum PreferPolicy {
+    HighestDataVersion, // prefer the candidate with the highest `DataVersion`
+    MostSections,       // prefer the candidate with the most populated `sections`/`Sections`
+    Largest,            // prefer the candidate with the largest decompressed payload
+}
+
+impl PreferPolicy {
+    /// A comparable score for `entry` under this policy, higher is better.
+    pub fn score(&self, entry: &RegionEntry) -> i64 {
+        match self {
+            PreferPolicy::HighestDataVersion => entry.data_version.unwrap_or(i32::MIN) as i64,
+            PreferPolicy::MostSections => entry.section_count as i64,
+            PreferPolicy::Largest => entry.payload_len as i64,
+        }
+    }
+}
+
+/// Per-region counts gathered while scanning, used to report what recovery would do (or did)
+/// without requiring a write.
+#[derive(Clone, Copy, Default)]
+pub struct RegionStats {
+    pub single_valid: u32, // chunks with a single, already-correct entry
+    pub single_untracked_duplicate: u32, // chunks with a current entry plus one untracked duplicate
+    pub multiple_untracked_duplicates: u32, // chunks with a current entry plus several untracked duplicates
+    pub dangling_header: u32, // chunks whose header pointer doesn't match any discovered entry
+    pub invalid_sectors: u32, // scanned sectors that didn't parse as a valid entry at all
+    pub recovered: u32,       // chunks whose header entry was actually rewritten
+}
+
+impl RegionStats {
+    pub fn merge(&mut self, other: &RegionStats) {
+        self.single_valid += other.single_valid;
+        self.single_untracked_duplicate += other.single_untracked_duplicate;
+        self.multiple_untracked_duplicates += other.multiple_untracked_duplicates;
+        self.dangling_header += other.dangling_header;
+        self.invalid_sectors += other.invalid_sectors;
+        self.recovered += other.recovered;
+    }
 }
 
 pub fn chunk_position_from_entry_idx(region_position: (i32, i32), entry_idx: u16) -> (i32, i32) {
@@ -44,6 +99,32 @@ pub fn set_header_entry(bytes: &mut [u8], header_offset: usize, sector_idx: usiz
     assert_eq!(size, written_size);
 }
 
+/// The second 4096-byte sector of a region file: 1024 big-endian Unix-second timestamps,
+/// indexed identically to the locations header.
+pub struct Timestamps<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Timestamps<'a> {
+    pub fn parse(region_bytes: &'a [u8]) -> Self {
+        Timestamps {
+            bytes: &region_bytes[SECTOR_SIZE..SECTOR_SIZE * 2],
+        }
+    }
+
+    pub fn get(&self, header_idx: usize) -> u32 {
+        read_bigendian_u32(self.bytes, header_idx * 4)
+    }
+}
+
+pub fn set_timestamp_entry(bytes: &mut [u8], header_idx: usize, value: u32) {
+    let offset = SECTOR_SIZE + header_idx * 4;
+    bytes[offset] = ((value >> 24) & 0xff) as u8;
+    bytes[offset + 1] = ((value >> 16) & 0xff) as u8;
+    bytes[offset + 2] = ((value >> 8) & 0xff) as u8;
+    bytes[offset + 3] = (value & 0xff) as u8;
+}
+
 pub fn read_bigendian_u32(bytes: &[u8], header_offset: usize) -> u32 {
     (bytes[header_offset + 3] as u32)
         | (bytes[header_offset + 2] as u32) << 8